@@ -0,0 +1,653 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Infrastructure backing the `pref!`/`set_pref!` macros: a generic,
+//! string-keyed view over a strongly-typed preferences struct, plus the
+//! dynamic value type used to carry preferences across that boundary
+//! (JSON files, command line flags, WebDriver capabilities, ...).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use notify::{watcher, RecursiveMode, Watcher};
+use serde_json::Value;
+
+/// How a single field of the generated `Prefs` struct is read from and
+/// written to a [`PrefValue`], keyed by the preference's dotted path
+/// (e.g. `"dom.testing.element.activation.enabled"`). These are emitted
+/// by the `servo_config_plugins::build_structs` macro, one per leaf
+/// field of the generated struct.
+pub struct Accessor<T> {
+    pub pref_name: &'static str,
+    pub getter: fn(&T) -> PrefValue,
+    pub setter: fn(&mut T, PrefValue) -> Result<(), PrefError>,
+}
+
+/// Callback invoked when a subscribed preference changes: `(path, old, new)`.
+type Observer = dyn Fn(&str, &PrefValue, &PrefValue) + Send + Sync;
+
+/// A handle returned by [`Preferences::subscribe`]. The subscription is
+/// live for as long as this handle (or a clone of it) is alive; drop it
+/// to unsubscribe. There is no explicit `unsubscribe` call because the
+/// registry only ever holds a `Weak` reference to the callback, so a
+/// dropped handle is enough to make it inert.
+#[derive(Clone)]
+pub struct Subscription(#[allow(dead_code)] Arc<Observer>);
+
+/// Whether `path` falls under `prefix`, the way Firefox's pref service
+/// matches a branch observer: `prefix` must match `path` exactly or end
+/// at a `.` boundary, so a subscription on `"dom.testing"` doesn't also
+/// fire for an unrelated sibling like `"dom.testing2.enabled"`. An empty
+/// prefix matches every path.
+fn path_matches_prefix(path: &str, prefix: &str) -> bool {
+    prefix.is_empty() || path == prefix || path.starts_with(&format!("{prefix}."))
+}
+
+/// A named source of preference values. A key can be defined by more
+/// than one layer at once (e.g. a user's `prefs.json` and a `--pref`
+/// command-line flag both touching `layout.threads`); the *effective*
+/// value is always the one from the highest-precedence layer that
+/// defines it. This is analogous to Alacritty merging its config file
+/// with CLI `Options`, generalized to N named sources so each can be
+/// inspected and rolled back independently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Layer {
+    /// The compiled-in defaults loaded from the `prefs.json` resource.
+    Default,
+    /// An enterprise/admin policy override.
+    Policy,
+    /// The user's `prefs.json` on disk, including live reloads from
+    /// [`Preferences::watch`].
+    UserFile,
+    /// A `--pref` command-line flag.
+    CommandLine,
+    /// A WebDriver session's `prefs` capability.
+    Capability,
+}
+
+impl Layer {
+    /// Highest to lowest precedence.
+    const PRECEDENCE: [Layer; 4] = [
+        Layer::Capability,
+        Layer::CommandLine,
+        Layer::UserFile,
+        Layer::Policy,
+    ];
+}
+
+/// A strongly-typed preferences struct (`T`, usually the generated
+/// `Prefs`), made addressable by the dotted string keys used in
+/// `prefs.json` and on the command line.
+pub struct Preferences<'a, T> {
+    /// The effective, merged value of every preference - what `pref!`
+    /// and [`Preferences::get`] read. Recomputed from `layers` (falling
+    /// back to `defaults`) whenever a layer changes.
+    values: RwLock<T>,
+    accessors: &'a [Accessor<T>],
+    observers: RwLock<Vec<(String, Weak<Observer>)>>,
+    /// The compiled-in value of every known preference, captured once
+    /// at construction. Acts as the implicit `Layer::Default` without
+    /// requiring `T: Clone`.
+    defaults: HashMap<&'static str, PrefValue>,
+    /// Per-layer overrides, consulted in [`Layer::PRECEDENCE`] order.
+    layers: RwLock<HashMap<Layer, HashMap<String, PrefValue>>>,
+}
+
+impl<'a, T> Preferences<'a, T> {
+    pub fn new(values: T, accessors: &'a [Accessor<T>]) -> Self {
+        let defaults = accessors
+            .iter()
+            .map(|accessor| (accessor.pref_name, (accessor.getter)(&values)))
+            .collect();
+        Self {
+            values: RwLock::new(values),
+            accessors,
+            observers: RwLock::new(vec![]),
+            defaults,
+            layers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Register `callback` to be run whenever a preference whose path
+    /// starts with `path_prefix` changes via [`Preferences::set_in_layer`]
+    /// or [`Preferences::set_all_in_layer`] (so this also covers a file
+    /// reload from [`Preferences::watch`], and WebDriver capability
+    /// layers). Pass an empty prefix to observe every preference.
+    ///
+    /// Mirrors how Firefox's preference service dispatches pref-change
+    /// notifications: the callback runs after the write lock has
+    /// already been released, so it is safe to read preferences (e.g.
+    /// via the `pref!` macro) from inside it without deadlocking.
+    ///
+    /// The subscription is cancelled by dropping the returned handle.
+    pub fn subscribe(
+        &self,
+        path_prefix: impl Into<String>,
+        callback: impl Fn(&str, &PrefValue, &PrefValue) + Send + Sync + 'static,
+    ) -> Subscription {
+        let callback: Arc<Observer> = Arc::new(callback);
+        self.observers
+            .write()
+            .unwrap()
+            .push((path_prefix.into(), Arc::downgrade(&callback)));
+        Subscription(callback)
+    }
+
+    /// Run every live observer whose prefix matches `path`, and drop
+    /// any whose handle has since been dropped. Must not be called
+    /// while `self.values`'s write lock is held, since an observer is
+    /// free to read preferences back out.
+    ///
+    /// Callbacks run against a snapshot of the observer list, taken
+    /// under a brief read lock that's released before any of them are
+    /// invoked. An observer is free to call `subscribe()` again, or to
+    /// write another observed preference (re-entering `notify`) - doing
+    /// either while still holding `observers`'s write lock would
+    /// deadlock on this same thread.
+    fn notify(&self, path: &str, old: &PrefValue, new: &PrefValue) {
+        if old == new {
+            return;
+        }
+        let observers = self.observers.read().unwrap().clone();
+        for (prefix, observer) in &observers {
+            if let Some(observer) = observer.upgrade() {
+                if path_matches_prefix(path, prefix) {
+                    observer(path, old, new);
+                }
+            }
+        }
+        self.observers
+            .write()
+            .unwrap()
+            .retain(|(_, observer)| observer.upgrade().is_some());
+    }
+
+    /// The underlying lock, used by the `pref!`/`set_pref!` macros to
+    /// read or write a single statically-known field directly.
+    pub fn values(&self) -> &RwLock<T> {
+        &self.values
+    }
+
+    fn accessor(&self, key: &str) -> Result<&Accessor<T>, PrefError> {
+        self.accessors
+            .iter()
+            .find(|accessor| accessor.pref_name == key)
+            .ok_or_else(|| PrefError::NoSuchPref(key.to_owned()))
+    }
+
+    /// Recompute the effective value of `key` from `layers`/`defaults`
+    /// and write it into `values`, notifying subscribers if it changed.
+    /// Called after any layer is written to or cleared.
+    fn recompute(&self, accessor: &Accessor<T>) -> Result<(), PrefError> {
+        let key = accessor.pref_name;
+        let winner = {
+            let layers = self.layers.read().unwrap();
+            Layer::PRECEDENCE
+                .iter()
+                .find_map(|layer| layers.get(layer).and_then(|overrides| overrides.get(key)))
+                .cloned()
+        };
+        let winner = match winner {
+            Some(value) => value,
+            None => self
+                .defaults
+                .get(key)
+                .cloned()
+                .ok_or_else(|| PrefError::NoSuchPref(key.to_owned()))?,
+        };
+
+        let (old, new) = {
+            let mut values = self.values.write().unwrap();
+            let old = (accessor.getter)(&values);
+            (accessor.setter)(&mut values, winner)?;
+            (old, (accessor.getter)(&values))
+        };
+        self.notify(key, &old, &new);
+        Ok(())
+    }
+
+    /// Set a single preference in `layer`. All-or-nothing: on failure
+    /// neither the layer nor the effective value are changed. Any
+    /// observers subscribed to this key via [`Preferences::subscribe`]
+    /// run after the new effective value has been committed and the
+    /// write lock released.
+    pub fn set_in_layer(&self, layer: Layer, key: &str, value: PrefValue) -> Result<(), PrefError> {
+        self.set_in_layer_tracked(layer, key, value)?;
+        Ok(())
+    }
+
+    /// Like [`Preferences::set_in_layer`], but also returns whatever
+    /// `layer` held for `key` before this call (`None` if it didn't
+    /// define `key` at all), so a caller can undo it later with
+    /// [`Preferences::restore_in_layer`]. On failure, `layer` and the
+    /// effective value are left exactly as they were: the speculative
+    /// insert below is rolled back before the error is returned, rather
+    /// than leaking a never-applied value into `layer`.
+    fn set_in_layer_tracked(
+        &self,
+        layer: Layer,
+        key: &str,
+        value: PrefValue,
+    ) -> Result<Option<PrefValue>, PrefError> {
+        let accessor = self.accessor(key)?;
+        let previous = self
+            .layers
+            .write()
+            .unwrap()
+            .entry(layer)
+            .or_default()
+            .insert(key.to_owned(), value);
+        if let Err(error) = self.recompute(accessor) {
+            self.restore_in_layer(layer, key.to_owned(), previous);
+            return Err(error);
+        }
+        Ok(previous)
+    }
+
+    /// Put `layer`'s value for `key` back to `previous` (removing `key`
+    /// entirely if it had none), then recompute the effective value.
+    /// Used to roll back a [`Preferences::set_in_layer_tracked`] call
+    /// that didn't end up sticking.
+    fn restore_in_layer(&self, layer: Layer, key: String, previous: Option<PrefValue>) {
+        {
+            let mut layers = self.layers.write().unwrap();
+            let overrides = layers.entry(layer).or_default();
+            match previous {
+                Some(previous) => {
+                    overrides.insert(key.clone(), previous);
+                },
+                None => {
+                    overrides.remove(&key);
+                },
+            }
+        }
+        if let Ok(accessor) = self.accessor(&key) {
+            let _ = self.recompute(accessor);
+        }
+    }
+
+    /// Set every preference in `iter` within `layer`, stopping at the
+    /// first error. Any preferences applied before the failing one
+    /// remain applied.
+    pub fn set_all_in_layer(
+        &self,
+        layer: Layer,
+        iter: impl Iterator<Item = (String, PrefValue)>,
+    ) -> Result<(), PrefError> {
+        for (key, value) in iter {
+            self.set_in_layer(layer, &key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Preferences::set_all_in_layer`], but a failure on any key
+    /// undoes every key already applied earlier in this same call, so
+    /// once it returns `layer` (and the effective values it feeds) is
+    /// never left half-updated. Keys are still committed one at a time:
+    /// while the call runs, readers and subscribers see each key as it
+    /// is applied, and again as it is rolled back after a failure.
+    /// Use this instead of `set_all_in_layer` whenever the
+    /// keys in `iter` should be applied as a single unit - e.g. a
+    /// WebDriver capability payload, where one malformed value must not
+    /// leave the rest of the payload in effect.
+    pub fn set_all_in_layer_atomic(
+        &self,
+        layer: Layer,
+        iter: impl Iterator<Item = (String, PrefValue)>,
+    ) -> Result<(), PrefError> {
+        let mut applied = vec![];
+        for (key, value) in iter {
+            match self.set_in_layer_tracked(layer, &key, value) {
+                Ok(previous) => applied.push((key, previous)),
+                Err(error) => {
+                    for (key, previous) in applied.into_iter().rev() {
+                        self.restore_in_layer(layer, key, previous);
+                    }
+                    return Err(error);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Preferences::set_all_in_layer`], but a failure on one key
+    /// does not prevent the rest from being applied: failing keys are
+    /// reported in the returned `Vec<PrefError>` and left out of
+    /// `layer` entirely, so they fall through to whatever the next
+    /// highest-precedence layer (or the compiled default) already has.
+    pub fn set_all_in_layer_lenient(
+        &self,
+        layer: Layer,
+        iter: impl Iterator<Item = (String, PrefValue)>,
+    ) -> Vec<PrefError> {
+        let mut errors = vec![];
+        for (key, value) in iter {
+            if let Err(error) = self.set_in_layer(layer, &key, value) {
+                errors.push(error);
+            }
+        }
+        errors
+    }
+
+    /// Remove every preference `layer` defines, falling each one back
+    /// to whatever the next highest-precedence layer (or the compiled
+    /// default) says. Lets a caller reset e.g. just the command-line
+    /// overrides on reload while preserving the user's `prefs.json`.
+    pub fn clear_layer(&self, layer: Layer) {
+        let cleared = self.layers.write().unwrap().remove(&layer);
+        let Some(cleared) = cleared else { return };
+        for key in cleared.keys() {
+            if let Ok(accessor) = self.accessor(key) {
+                let _ = self.recompute(accessor);
+            }
+        }
+    }
+
+    /// Which layer the effective value of `key` currently comes from.
+    pub fn source_of(&self, key: &str) -> Result<Layer, PrefError> {
+        self.accessor(key)?;
+        let layers = self.layers.read().unwrap();
+        Ok(Layer::PRECEDENCE
+            .iter()
+            .find(|layer| layers.get(layer).is_some_and(|o| o.contains_key(key)))
+            .copied()
+            .unwrap_or(Layer::Default))
+    }
+
+    pub fn get(&self, key: &str) -> Result<PrefValue, PrefError> {
+        let accessor = self.accessor(key)?;
+        let values = self.values.read().unwrap();
+        Ok((accessor.getter)(&values))
+    }
+}
+
+impl<'a, T> Preferences<'a, T>
+where
+    T: Send + Sync + 'static,
+{
+    /// Watch `path` for changes and keep the `Layer::UserFile` layer in
+    /// sync with it for as long as the process runs. `parse` turns the
+    /// raw file contents into the string-keyed map `set_all_in_layer`
+    /// expects (servo uses [`crate::prefs::read_prefs_map`] for this).
+    ///
+    /// Rapid write bursts (e.g. an editor doing a save-as-rename) are
+    /// coalesced into a single reload, and the parent directory is
+    /// watched rather than the file itself so that atomic
+    /// replace-the-file saves (which swap the inode) are still picked
+    /// up. Each reload replaces the `UserFile` layer wholesale (so a key
+    /// removed from the file reverts to the next layer down, not a
+    /// stale leftover value) without disturbing any other layer. A
+    /// reload that fails to parse is logged and otherwise ignored: the
+    /// currently-live preferences are left untouched, and the lock is
+    /// never left poisoned.
+    pub fn watch<F>(&'static self, path: PathBuf, parse: F)
+    where
+        F: Fn(&str) -> Result<HashMap<String, PrefValue>, PrefError> + Send + 'static,
+    {
+        thread::Builder::new()
+            .name("pref-watcher".to_owned())
+            .spawn(move || self.watch_loop(path, parse))
+            .expect("Failed to spawn preference watcher thread");
+    }
+
+    fn watch_loop<F>(&self, path: PathBuf, parse: F)
+    where
+        F: Fn(&str) -> Result<HashMap<String, PrefValue>, PrefError>,
+    {
+        let (tx, rx) = channel();
+        // Debounce rapid write bursts (e.g. an editor's save-as-rename
+        // sequence) into a single coalesced event, the way Alacritty's
+        // config watcher does.
+        let mut watcher = match watcher(tx, Duration::from_millis(250)) {
+            Ok(watcher) => watcher,
+            Err(error) => {
+                warn!("Failed to create preference file watcher: {:?}", error);
+                return;
+            },
+        };
+
+        // Watch the parent directory, not the file itself: editors and
+        // `mv`-based atomic saves replace the inode entirely, which
+        // would silently orphan a watch on the file path.
+        let watch_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        if let Err(error) = watcher.watch(watch_dir, RecursiveMode::NonRecursive) {
+            warn!("Failed to watch preference directory {:?}: {:?}", watch_dir, error);
+            return;
+        }
+
+        for event in rx {
+            if !Self::event_affects(&event, &path) {
+                continue;
+            }
+            self.reload(&path, &parse);
+        }
+    }
+
+    fn event_affects(event: &notify::DebouncedEvent, path: &Path) -> bool {
+        use notify::DebouncedEvent::*;
+        match event {
+            Write(p) | Create(p) | Rename(_, p) => p == path,
+            _ => false,
+        }
+    }
+
+    fn reload<F>(&self, path: &Path, parse: &F)
+    where
+        F: Fn(&str) -> Result<HashMap<String, PrefValue>, PrefError>,
+    {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                warn!("Failed to read preference file {:?}: {:?}", path, error);
+                return;
+            },
+        };
+
+        match parse(&contents) {
+            Ok(prefs) => {
+                self.clear_layer(Layer::UserFile);
+                let errors = self.set_all_in_layer_lenient(Layer::UserFile, prefs.into_iter());
+                for error in errors {
+                    warn!("Ignoring bad preference while reloading {:?}: {:?}", path, error);
+                }
+                debug!("Reloaded preferences from {:?}", path);
+            },
+            Err(error) => {
+                warn!(
+                    "Failed to parse preference file {:?}, leaving live preferences untouched: {:?}",
+                    path, error
+                );
+            },
+        }
+    }
+}
+
+/// A value that can be read from or written to a preference, independent
+/// of the static type of the field it backs. This is the currency used
+/// when preferences cross a text boundary: `prefs.json`, the command
+/// line, or a WebDriver `prefs` capability.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrefValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<PrefValue>),
+    Missing,
+}
+
+impl PrefValue {
+    pub fn from_json_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(PrefValue::Bool(*b)),
+            Value::Number(n) if n.is_i64() => Some(PrefValue::Int(n.as_i64().unwrap())),
+            Value::Number(n) if n.is_f64() => Some(PrefValue::Float(n.as_f64().unwrap())),
+            Value::String(s) => Some(PrefValue::Str(s.to_owned())),
+            Value::Array(values) => values
+                .iter()
+                .map(PrefValue::from_json_value)
+                .collect::<Option<Vec<_>>>()
+                .map(PrefValue::Array),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for PrefValue {
+    fn from(value: bool) -> Self {
+        PrefValue::Bool(value)
+    }
+}
+
+impl From<i64> for PrefValue {
+    fn from(value: i64) -> Self {
+        PrefValue::Int(value)
+    }
+}
+
+impl From<f64> for PrefValue {
+    fn from(value: f64) -> Self {
+        PrefValue::Float(value)
+    }
+}
+
+impl From<String> for PrefValue {
+    fn from(value: String) -> Self {
+        PrefValue::Str(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum PrefError {
+    NoSuchPref(String),
+    InvalidValue(String),
+    JsonParseErr(serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    //! Exercises the layering/precedence logic directly against a small
+    //! standalone `T`, independent of the generated `Prefs` struct.
+
+    use super::*;
+
+    #[test]
+    fn path_matches_prefix_requires_a_dot_boundary() {
+        assert!(path_matches_prefix("dom.testing.enabled", "dom.testing"));
+        assert!(path_matches_prefix("dom.testing", "dom.testing"));
+        assert!(!path_matches_prefix("dom.testing2.enabled", "dom.testing"));
+        assert!(path_matches_prefix("dom.testing.enabled", ""));
+    }
+
+    #[derive(Default)]
+    struct TestPrefs {
+        enabled: bool,
+        threshold: i64,
+    }
+
+    fn test_accessors() -> Vec<Accessor<TestPrefs>> {
+        vec![
+            Accessor {
+                pref_name: "test.enabled",
+                getter: |prefs| PrefValue::Bool(prefs.enabled),
+                setter: |prefs, value| match value {
+                    PrefValue::Bool(value) => {
+                        prefs.enabled = value;
+                        Ok(())
+                    },
+                    _ => Err(PrefError::InvalidValue("test.enabled expects a bool".to_owned())),
+                },
+            },
+            Accessor {
+                pref_name: "test.threshold",
+                getter: |prefs| PrefValue::Int(prefs.threshold),
+                setter: |prefs, value| match value {
+                    PrefValue::Int(value) => {
+                        prefs.threshold = value;
+                        Ok(())
+                    },
+                    _ => Err(PrefError::InvalidValue("test.threshold expects an int".to_owned())),
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn higher_precedence_layer_wins_and_clearing_it_falls_back() {
+        let accessors = test_accessors();
+        let prefs = Preferences::new(TestPrefs::default(), &accessors);
+
+        prefs.set_in_layer(Layer::UserFile, "test.threshold", PrefValue::Int(5)).unwrap();
+        prefs
+            .set_in_layer(Layer::CommandLine, "test.threshold", PrefValue::Int(9))
+            .unwrap();
+        assert_eq!(prefs.get("test.threshold").unwrap(), PrefValue::Int(9));
+        assert_eq!(prefs.source_of("test.threshold").unwrap(), Layer::CommandLine);
+
+        prefs.clear_layer(Layer::CommandLine);
+        assert_eq!(prefs.get("test.threshold").unwrap(), PrefValue::Int(5));
+        assert_eq!(prefs.source_of("test.threshold").unwrap(), Layer::UserFile);
+    }
+
+    #[test]
+    fn set_in_layer_rejects_wrong_type_without_leaking_the_override() {
+        let accessors = test_accessors();
+        let prefs = Preferences::new(TestPrefs::default(), &accessors);
+
+        let result = prefs.set_in_layer(Layer::UserFile, "test.enabled", PrefValue::Int(1));
+        assert!(result.is_err());
+
+        // The live value must be untouched, and so must its provenance:
+        // a rejected value must not leak into `layers`, or `source_of`
+        // would claim the (unchanged) effective value came from
+        // `Layer::UserFile`.
+        assert_eq!(prefs.get("test.enabled").unwrap(), PrefValue::Bool(false));
+        assert_eq!(prefs.source_of("test.enabled").unwrap(), Layer::Default);
+    }
+
+    #[test]
+    fn set_all_in_layer_lenient_applies_valid_keys_and_reports_the_rest() {
+        let accessors = test_accessors();
+        let prefs = Preferences::new(TestPrefs::default(), &accessors);
+
+        let errors = prefs.set_all_in_layer_lenient(
+            Layer::UserFile,
+            vec![
+                ("test.enabled".to_owned(), PrefValue::Bool(true)),
+                ("test.threshold".to_owned(), PrefValue::Str("oops".to_owned())),
+                ("test.unknown".to_owned(), PrefValue::Bool(true)),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(prefs.get("test.enabled").unwrap(), PrefValue::Bool(true));
+        assert_eq!(prefs.get("test.threshold").unwrap(), PrefValue::Int(0));
+        assert_eq!(prefs.source_of("test.threshold").unwrap(), Layer::Default);
+    }
+
+    #[test]
+    fn set_all_in_layer_atomic_applies_nothing_on_a_single_bad_value() {
+        let accessors = test_accessors();
+        let prefs = Preferences::new(TestPrefs::default(), &accessors);
+
+        let result = prefs.set_all_in_layer_atomic(
+            Layer::Capability,
+            vec![
+                ("test.enabled".to_owned(), PrefValue::Bool(true)),
+                ("test.threshold".to_owned(), PrefValue::Str("oops".to_owned())),
+            ]
+            .into_iter(),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(prefs.get("test.enabled").unwrap(), PrefValue::Bool(false));
+        assert_eq!(prefs.source_of("test.enabled").unwrap(), Layer::Default);
+    }
+}
@@ -4,13 +4,14 @@
 
 use std::borrow::ToOwned;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 use embedder_traits::resources::{self, Resource};
 use gen::Prefs;
 use lazy_static::lazy_static;
 use serde_json::{self, Value};
 
-use crate::pref_util::Preferences;
+use crate::pref_util::{Layer, Preferences};
 pub use crate::pref_util::{PrefError, PrefValue};
 
 lazy_static! {
@@ -56,11 +57,20 @@ pub fn pref_map() -> &'static Preferences<'static, Prefs> {
 }
 
 pub fn add_user_prefs(prefs: HashMap<String, PrefValue>) {
-    if let Err(error) = PREFS.set_all(prefs.into_iter()) {
+    if let Err(error) = PREFS.set_all_in_layer(Layer::UserFile, prefs.into_iter()) {
         panic!("Error setting preference: {:?}", error);
     }
 }
 
+/// Watch `path` for edits and keep the `Layer::UserFile` layer in sync
+/// with it for the lifetime of the process. Intended for development
+/// use, so that DOM/layout feature flags can be flipped without
+/// restarting the session; see [`Preferences::watch`] for the reload
+/// semantics.
+pub fn watch_user_prefs_file(path: PathBuf) {
+    PREFS.watch(path, read_prefs_map);
+}
+
 pub fn read_prefs_map(txt: &str) -> Result<HashMap<String, PrefValue>, PrefError> {
     let prefs: HashMap<String, Value> =
         serde_json::from_str(txt).map_err(|e| PrefError::JsonParseErr(e))?;
@@ -97,6 +107,103 @@ pub fn read_prefs_map(txt: &str) -> Result<HashMap<String, PrefValue>, PrefError
         .collect()
 }
 
+/// Like [`read_prefs_map`], but a malformed entry does not discard the
+/// rest of the file: each key is converted independently, and any that
+/// fail (wrong JSON type, or a nested array containing one) are left
+/// out of the returned map and reported in the accompanying
+/// `Vec<PrefError>` instead of aborting the parse. A key left out of
+/// the map is not set when the map is applied (e.g. through
+/// [`add_user_prefs_checked`]), so it keeps whatever value it already
+/// had, such as the compiled default on first boot.
+pub fn read_prefs_map_lenient(txt: &str) -> Result<(HashMap<String, PrefValue>, Vec<PrefError>), PrefError> {
+    let prefs: HashMap<String, Value> =
+        serde_json::from_str(txt).map_err(PrefError::JsonParseErr)?;
+    let mut map = HashMap::with_capacity(prefs.len());
+    let mut errors = vec![];
+    for (key, value) in prefs {
+        match PrefValue::from_json_value(&value) {
+            Some(v) => {
+                map.insert(key, v);
+            },
+            None => errors.push(PrefError::InvalidValue(format!(
+                "Invalid value for {}: {}",
+                key, value
+            ))),
+        }
+    }
+    Ok((map, errors))
+}
+
+/// Like [`add_user_prefs`], but a preference that fails to apply (an
+/// unknown path, or a value of the wrong type for its path) does not
+/// panic and does not prevent the rest of `prefs` from being applied.
+/// The session still boots with every valid preference applied; the
+/// caller gets back the set of warnings to surface to the user.
+pub fn add_user_prefs_checked(prefs: HashMap<String, PrefValue>) -> Vec<PrefError> {
+    PREFS.set_all_in_layer_lenient(Layer::UserFile, prefs.into_iter())
+}
+
+/// Flatten a JSON value understood as a preferences dialect into the
+/// dotted-path map `Preferences` deals in. Both forms WebDriver's
+/// `prefs` capability can show up in are accepted: a flat object whose
+/// keys are already dotted paths (`{"dom.testing.enabled": true}`), or
+/// nested objects following the same shape as the `Prefs` struct
+/// (`{"dom": {"testing": {"enabled": true}}}`); the two can also be
+/// mixed.
+fn flatten_prefs_value(
+    value: &Value,
+    path: &str,
+    out: &mut HashMap<String, PrefValue>,
+) -> Result<(), PrefError> {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                flatten_prefs_value(value, &path, out)?;
+            }
+            Ok(())
+        },
+        _ => match PrefValue::from_json_value(value) {
+            Some(v) => {
+                out.insert(path.to_owned(), v);
+                Ok(())
+            },
+            None => Err(PrefError::InvalidValue(format!(
+                "Invalid value for {}: {}",
+                path, value
+            ))),
+        },
+    }
+}
+
+/// Apply a WebDriver-style `prefs` capability supplied at session
+/// creation: a flat or nested JSON object of preference overrides (see
+/// `flatten_prefs_value`), whose leaf values take the same JSON types
+/// [`read_prefs_map`] accepts; unlike `read_prefs_map`, nested objects
+/// are accepted. Applied as the
+/// `Layer::Capability` layer, which takes precedence over every other
+/// source; the whole payload is applied as a single unit, so a
+/// malformed capability leaves preferences untouched rather than
+/// applying half of it.
+pub fn apply_capability_prefs(value: Value) -> Result<(), PrefError> {
+    let mut overrides = HashMap::new();
+    flatten_prefs_value(&value, "", &mut overrides)?;
+
+    PREFS.set_all_in_layer_atomic(Layer::Capability, overrides.into_iter())
+}
+
+/// Clear the `Layer::Capability` layer, restoring every preference it
+/// overrode to whatever the next highest-precedence source (the user's
+/// `prefs.json`, a `--pref` flag, ...) says, without disturbing any of
+/// them. Call this when a WebDriver session closes.
+pub fn reset_capability_prefs() {
+    PREFS.clear_layer(Layer::Capability);
+}
+
 mod gen {
     use serde::{Deserialize, Serialize};
     use servo_config_plugins::build_structs;
@@ -851,3 +958,49 @@ mod gen {
         context_creation_error: bool,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_prefs_map_lenient_applies_every_valid_key() {
+        let (map, errors) = read_prefs_map_lenient(
+            r#"{"dom.testing.enabled": true, "layout.threads": 4}"#,
+        )
+        .unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(map.get("dom.testing.enabled"), Some(&PrefValue::Bool(true)));
+        assert_eq!(map.get("layout.threads"), Some(&PrefValue::Int(4)));
+    }
+
+    #[test]
+    fn read_prefs_map_lenient_skips_bad_keys_and_reports_them() {
+        let (map, errors) = read_prefs_map_lenient(
+            r#"{"dom.testing.enabled": true, "layout.threads": {}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(map.get("dom.testing.enabled"), Some(&PrefValue::Bool(true)));
+        assert!(!map.contains_key("layout.threads"));
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn read_prefs_map_lenient_skips_an_array_with_one_bad_element() {
+        let (map, errors) =
+            read_prefs_map_lenient(r#"{"dom.testing.array": [1, {}, 3]}"#).unwrap();
+
+        assert!(!map.contains_key("dom.testing.array"));
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn read_prefs_map_lenient_rejects_malformed_json_outright() {
+        assert!(matches!(
+            read_prefs_map_lenient("not json"),
+            Err(PrefError::JsonParseErr(_))
+        ));
+    }
+}
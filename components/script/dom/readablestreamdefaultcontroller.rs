@@ -2,53 +2,604 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::cell::Cell;
+use std::collections::VecDeque;
+
 use dom_struct::dom_struct;
-use js::jsapi::{
-    AutoRequireNoGC, HandleObject, HandleValue, Heap, IsReadableStream, JSContext, JSObject,
-};
-use js::jsval::{JSVal, ObjectValue, UndefinedValue};
-use js::rust::{HandleObject as SafeHandleObject, HandleValue as SafeHandleValue, IntoHandle};
+use js::jsapi::{HandleObject, HandleValueArray, Heap, JS_CallFunctionValue};
+use js::jsval::{JSVal, NullValue, ObjectValue, UndefinedValue};
+use js::rust::{HandleValue as SafeHandleValue, IntoHandle};
 
+use crate::dom::bindings::cell::DomRefCell;
 use crate::dom::bindings::codegen::Bindings::ReadableStreamDefaultControllerBinding::ReadableStreamDefaultControllerMethods;
 use crate::dom::bindings::conversions::{ConversionBehavior, ConversionResult};
 use crate::dom::bindings::error::Error;
 use crate::dom::bindings::import::module::Fallible;
 use crate::dom::bindings::reflector::{reflect_dom_object, DomObject, Reflector};
-use crate::dom::bindings::root::DomRoot;
+use crate::dom::bindings::root::{DomRoot, MutNullableDom};
 use crate::dom::bindings::settings_stack::{AutoEntryScript, AutoIncumbentScript};
 use crate::dom::bindings::utils::get_dictionary_property;
 use crate::dom::globalscope::GlobalScope;
 use crate::dom::promise::Promise;
+use crate::dom::promisenativehandler::PromiseNativeHandler;
+use crate::dom::readablestream::ReadableStream;
 use crate::js::conversions::FromJSValConvertible;
 use crate::realms::{enter_realm, InRealm};
 use crate::script_runtime::JSContext as SafeJSContext;
 
+/// A queued chunk together with the size the strategy's size algorithm
+/// assigned to it, rooted for as long as it sits in the queue.
+/// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-queue>
+#[derive(JSTraceable, MallocSizeOf)]
+#[crown::unrooted_must_root_lint::must_root]
+struct QueueEntry {
+    #[ignore_malloc_size_of = "mozjs"]
+    chunk: Heap<JSVal>,
+    size: f64,
+}
+
+impl QueueEntry {
+    fn new(chunk: SafeHandleValue, size: f64) -> Self {
+        let entry = QueueEntry {
+            chunk: Heap::default(),
+            size,
+        };
+        entry.chunk.set(chunk.get());
+        entry
+    }
+}
+
 /// <https://streams.spec.whatwg.org/#rs-default-controller-class-definition>
 #[dom_struct]
 pub struct ReadableStreamDefaultController {
     reflector_: Reflector,
+
+    stream: MutNullableDom<ReadableStream>,
+
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-queue>
+    queue: DomRefCell<VecDeque<QueueEntry>>,
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-queuetotalsize>
+    queue_total_size: Cell<f64>,
+
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-started>
+    started: Cell<bool>,
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-closerequested>
+    close_requested: Cell<bool>,
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-pullagain>
+    pull_again: Cell<bool>,
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-pulling>
+    pulling: Cell<bool>,
+
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-strategyhwm>
+    strategy_hwm: Cell<f64>,
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-strategysizealgorithm>
+    #[ignore_malloc_size_of = "mozjs"]
+    strategy_size_algorithm: Heap<JSVal>,
+
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-pullalgorithm>
+    #[ignore_malloc_size_of = "mozjs"]
+    pull_algorithm: Heap<JSVal>,
+    /// <https://streams.spec.whatwg.org/#readablestreamdefaultcontroller-cancelalgorithm>
+    #[ignore_malloc_size_of = "mozjs"]
+    cancel_algorithm: Heap<JSVal>,
+}
+
+impl ReadableStreamDefaultController {
+    fn new_inherited() -> ReadableStreamDefaultController {
+        ReadableStreamDefaultController {
+            reflector_: Reflector::new(),
+            stream: MutNullableDom::new(None),
+            queue: DomRefCell::new(VecDeque::new()),
+            queue_total_size: Cell::new(0.0),
+            started: Cell::new(false),
+            close_requested: Cell::new(false),
+            pull_again: Cell::new(false),
+            pulling: Cell::new(false),
+            strategy_hwm: Cell::new(0.0),
+            strategy_size_algorithm: Heap::default(),
+            pull_algorithm: Heap::default(),
+            cancel_algorithm: Heap::default(),
+        }
+    }
+
+    fn new(global: &GlobalScope) -> DomRoot<ReadableStreamDefaultController> {
+        reflect_dom_object(Box::new(Self::new_inherited()), global)
+    }
+
+    fn stream(&self) -> DomRoot<ReadableStream> {
+        self.stream
+            .get()
+            .expect("ReadableStreamDefaultController used before it was set up")
+    }
+
+    /// Whether the controller may still enqueue chunks or request a
+    /// close: the stream must be readable, and a close must not already
+    /// have been requested. Used as a guard by both paths.
+    fn can_close_or_enqueue(&self) -> bool {
+        !self.close_requested.get() && self.stream().is_readable()
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-get-desired-size>
+    fn desired_size(&self) -> Option<f64> {
+        let stream = self.stream();
+        if stream.is_errored() {
+            None
+        } else if stream.is_closed() {
+            Some(0.0)
+        } else {
+            Some(self.strategy_hwm.get() - self.queue_total_size.get())
+        }
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-should-call-pull>
+    fn should_call_pull(&self) -> bool {
+        if !self.can_close_or_enqueue() || !self.started.get() {
+            return false;
+        }
+        let stream = self.stream();
+        if stream.has_default_reader() && stream.has_pending_read_requests() {
+            return true;
+        }
+        self.desired_size().is_some_and(|size| size > 0.0)
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-clear-algorithms>
+    fn clear_algorithms(&self) {
+        self.pull_algorithm.set(NullValue());
+        self.cancel_algorithm.set(NullValue());
+        self.strategy_size_algorithm.set(NullValue());
+    }
+
+    /// <https://streams.spec.whatwg.org/#reset-queue>
+    fn reset_queue(&self) {
+        self.queue.borrow_mut().clear();
+        self.queue_total_size.set(0.0);
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-error>
+    fn error_with_jsval(&self, cx: SafeJSContext, value: SafeHandleValue) {
+        let stream = self.stream();
+        if !stream.is_readable() {
+            return;
+        }
+        self.reset_queue();
+        self.clear_algorithms();
+        stream.error(cx, value);
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-close>
+    fn close(&self) {
+        if !self.can_close_or_enqueue() {
+            return;
+        }
+        let stream = self.stream();
+        if self.request_close() {
+            stream.close();
+        }
+    }
+
+    /// The queue-emptiness branch of `close()`: records that a close was
+    /// requested and, if the queue is already empty, clears the
+    /// algorithms now rather than waiting for the queue to drain.
+    /// Returns whether the queue was empty (i.e. whether the caller
+    /// should also close the underlying stream). Split out from
+    /// `close()` so it can be unit tested without a live stream.
+    fn request_close(&self) -> bool {
+        self.close_requested.set(true);
+        let queue_is_empty = self.queue.borrow().is_empty();
+        if queue_is_empty {
+            self.clear_algorithms();
+        }
+        queue_is_empty
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-enqueue>
+    fn enqueue(&self, cx: SafeJSContext, chunk: SafeHandleValue) -> Fallible<()> {
+        if !self.can_close_or_enqueue() {
+            return Ok(());
+        }
+
+        let stream = self.stream();
+        if stream.has_default_reader() && stream.has_pending_read_requests() {
+            stream.fulfill_read_request(cx, chunk, false);
+        } else {
+            let size = match self.call_size_algorithm(cx, chunk) {
+                Ok(size) if size.is_finite() && size >= 0.0 => size,
+                Ok(size) => {
+                    let error = Error::Range(format!("Invalid chunk size: {}", size));
+                    self.error_from_rust(cx, &error);
+                    return Err(error);
+                },
+                Err(error) => {
+                    self.error_from_rust(cx, &error);
+                    return Err(error);
+                },
+            };
+
+            self.queue.borrow_mut().push_back(QueueEntry::new(chunk, size));
+            self.queue_total_size.set(self.queue_total_size.get() + size);
+        }
+
+        self.call_pull_if_needed(cx);
+        Ok(())
+    }
+
+    /// Converts a Rust-side [`Error`] to a `JSVal` and runs the error
+    /// steps with it, so a thrown size-algorithm exception or an
+    /// invalid-size violation ends up erroring the stream the same way
+    /// a script-triggered `error()` call would.
+    fn error_from_rust(&self, cx: SafeJSContext, error: &Error) {
+        rooted!(in(*cx) let mut exception = UndefinedValue());
+        let _ = error.clone().to_jsval(*cx, &self.global(), exception.handle_mut());
+        self.error_with_jsval(cx, exception.handle());
+    }
+
+    /// The reentrancy guard in
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-call-pull-if-needed>:
+    /// if a pull is already in flight, just record that another is
+    /// wanted once it settles instead of running two concurrently.
+    /// Returns whether the caller should go ahead and invoke the pull
+    /// algorithm now. Split out from `call_pull_if_needed` so this flag
+    /// bookkeeping can be unit tested without a live stream/`JSContext`.
+    fn begin_pull(&self) -> bool {
+        if self.pulling.get() {
+            self.pull_again.set(true);
+            return false;
+        }
+        assert!(!self.pull_again.get());
+        self.pulling.set(true);
+        true
+    }
+
+    /// <https://streams.spec.whatwg.org/#readable-stream-default-controller-call-pull-if-needed>
+    fn call_pull_if_needed(&self, cx: SafeJSContext) {
+        if !self.should_call_pull() {
+            return;
+        }
+        if !self.begin_pull() {
+            return;
+        }
+
+        match self.call_pull_algorithm(cx) {
+            Ok(promise) => {
+                let on_fulfill = DomRoot::from_ref(self);
+                let on_reject = DomRoot::from_ref(self);
+                let handler = PromiseNativeHandler::new(
+                    &self.global(),
+                    Some(Box::new(move |cx: SafeJSContext, _value: SafeHandleValue| {
+                        on_fulfill.pulling.set(false);
+                        if on_fulfill.pull_again.get() {
+                            on_fulfill.pull_again.set(false);
+                            on_fulfill.call_pull_if_needed(cx);
+                        }
+                    })),
+                    Some(Box::new(move |cx: SafeJSContext, value: SafeHandleValue| {
+                        on_reject.error_with_jsval(cx, value);
+                    })),
+                );
+                let in_realm_proof = enter_realm(&*self.global());
+                promise.append_native_handler(&handler, InRealm::Already(&in_realm_proof));
+            },
+            Err(error) => {
+                self.pulling.set(false);
+                self.error_from_rust(cx, &error);
+            },
+        }
+    }
+
+    /// Invoke `strategySizeAlgorithm` on `chunk`. A missing algorithm
+    /// (no `size` callback was supplied) means every chunk counts for
+    /// `1`, per <https://streams.spec.whatwg.org/#make-size-algorithm-from-size-function>.
+    fn call_size_algorithm(&self, cx: SafeJSContext, chunk: SafeHandleValue) -> Fallible<f64> {
+        rooted!(in(*cx) let algorithm = self.strategy_size_algorithm.get());
+        if algorithm.get().is_null_or_undefined() {
+            return Ok(1.0);
+        }
+
+        rooted!(in(*cx) let mut rval = UndefinedValue());
+        let args = [chunk.get()];
+        let ok = unsafe {
+            JS_CallFunctionValue(
+                *cx,
+                HandleObject::null(),
+                algorithm.handle().into_handle(),
+                &HandleValueArray::from_rooted_slice(&args),
+                rval.handle_mut().into_handle_mut(),
+            )
+        };
+        if !ok {
+            return Err(Error::JSFailed);
+        }
+
+        match f64::from_jsval(*cx, rval.handle(), ConversionBehavior::Default) {
+            Ok(ConversionResult::Success(size)) => Ok(size),
+            Ok(ConversionResult::Failure(message)) => Err(Error::Type(message.into_owned())),
+            Err(()) => Err(Error::JSFailed),
+        }
+    }
+
+    /// Invoke `pullAlgorithm`, returning a promise resolved with
+    /// `undefined` when no algorithm was supplied.
+    fn call_pull_algorithm(&self, cx: SafeJSContext) -> Fallible<DomRoot<Promise>> {
+        let in_realm_proof = enter_realm(&*self.global());
+        let comp = InRealm::Already(&in_realm_proof);
+
+        rooted!(in(*cx) let algorithm = self.pull_algorithm.get());
+        if algorithm.get().is_null_or_undefined() {
+            let promise = Promise::new_in_current_realm(comp);
+            promise.resolve_native(&());
+            return Ok(promise);
+        }
+
+        rooted!(in(*cx) let mut rval = UndefinedValue());
+        let args = [ObjectValue(self.reflector().get_jsobject().get())];
+        let ok = unsafe {
+            JS_CallFunctionValue(
+                *cx,
+                HandleObject::null(),
+                algorithm.handle().into_handle(),
+                &HandleValueArray::from_rooted_slice(&args),
+                rval.handle_mut().into_handle_mut(),
+            )
+        };
+        if !ok {
+            return Err(Error::JSFailed);
+        }
+
+        let promise = Promise::new_in_current_realm(comp);
+        promise.resolve_native(&rval.handle());
+        Ok(promise)
+    }
 }
 
 impl ReadableStreamDefaultControllerMethods for ReadableStreamDefaultController {
+    /// <https://streams.spec.whatwg.org/#rs-default-controller-desired-size>
     fn GetDesiredSize(&self) -> Option<f64> {
-        todo!()
+        self.desired_size()
     }
 
+    /// <https://streams.spec.whatwg.org/#rs-default-controller-close>
     fn Close(&self) -> Fallible<()> {
-        todo!()
+        if !self.can_close_or_enqueue() {
+            return Err(Error::Type(
+                "Cannot close a readable stream that is not readable, or whose close has already been requested"
+                    .to_owned(),
+            ));
+        }
+        self.close();
+        Ok(())
     }
 
+    /// <https://streams.spec.whatwg.org/#rs-default-controller-enqueue>
     fn Enqueue(&self, cx: SafeJSContext, chunk: SafeHandleValue) -> Fallible<()> {
-        todo!()
+        self.enqueue(cx, chunk)
     }
 
+    /// <https://streams.spec.whatwg.org/#rs-default-controller-error>
     fn Error(&self, cx: SafeJSContext, e: SafeHandleValue) -> Fallible<()> {
-        todo!()
+        self.error_with_jsval(cx, e);
+        Ok(())
     }
 }
 
+/// <https://streams.spec.whatwg.org/#set-up-readable-stream-default-controller>
+#[allow(clippy::too_many_arguments)]
+fn setup_readable_stream_default_controller(
+    cx: SafeJSContext,
+    stream: &ReadableStream,
+    controller: &ReadableStreamDefaultController,
+    start_algorithm: SafeHandleValue,
+    pull_algorithm: SafeHandleValue,
+    cancel_algorithm: SafeHandleValue,
+    high_water_mark: f64,
+    size_algorithm: SafeHandleValue,
+) -> Fallible<()> {
+    controller.stream.set(Some(stream));
+    controller.reset_queue();
+    controller.started.set(false);
+    controller.close_requested.set(false);
+    controller.pull_again.set(false);
+    controller.pulling.set(false);
+    controller.strategy_size_algorithm.set(size_algorithm.get());
+    controller.strategy_hwm.set(high_water_mark);
+    controller.pull_algorithm.set(pull_algorithm.get());
+    controller.cancel_algorithm.set(cancel_algorithm.get());
+
+    stream.set_default_controller(controller);
+
+    let in_realm_proof = enter_realm(stream);
+    let comp = InRealm::Already(&in_realm_proof);
+
+    let start_promise = if start_algorithm.is_null_or_undefined() {
+        let promise = Promise::new_in_current_realm(comp);
+        promise.resolve_native(&());
+        promise
+    } else {
+        let _entry_script = AutoEntryScript::new(&controller.global());
+        let _incumbent_script = AutoIncumbentScript::new(&controller.global());
+
+        rooted!(in(*cx) let mut rval = UndefinedValue());
+        let args = [ObjectValue(controller.reflector().get_jsobject().get())];
+        let ok = unsafe {
+            JS_CallFunctionValue(
+                *cx,
+                HandleObject::null(),
+                start_algorithm.into(),
+                &HandleValueArray::from_rooted_slice(&args),
+                rval.handle_mut().into_handle_mut(),
+            )
+        };
+        if !ok {
+            // `start()` threw synchronously. Per step 9 of
+            // <https://streams.spec.whatwg.org/#set-up-readable-stream-default-controller>,
+            // this must propagate out of the `ReadableStream`
+            // constructor itself rather than merely error the stream.
+            // The exception is still pending on `cx`, so just bail out
+            // and let it surface through the bindings layer, the same
+            // way `call_size_algorithm`/`call_pull_algorithm` do.
+            return Err(Error::JSFailed);
+        }
+
+        let promise = Promise::new_in_current_realm(comp);
+        promise.resolve_native(&rval.handle());
+        promise
+    };
+
+    let on_fulfill = DomRoot::from_ref(controller);
+    let on_reject = DomRoot::from_ref(controller);
+    let handler = PromiseNativeHandler::new(
+        &controller.global(),
+        Some(Box::new(move |cx: SafeJSContext, _value: SafeHandleValue| {
+            on_fulfill.started.set(true);
+            assert!(!on_fulfill.pulling.get());
+            assert!(!on_fulfill.pull_again.get());
+            on_fulfill.call_pull_if_needed(cx);
+        })),
+        Some(Box::new(move |cx: SafeJSContext, value: SafeHandleValue| {
+            on_reject.error_with_jsval(cx, value);
+        })),
+    );
+    start_promise.append_native_handler(&handler, comp);
+    Ok(())
+}
+
 /// <https://streams.spec.whatwg.org/#set-up-readable-stream-default-controller-from-underlying-source>
-pub fn setup_readable_stream_default_controller_from_underlying_source() {}
+pub fn setup_readable_stream_default_controller_from_underlying_source(
+    cx: SafeJSContext,
+    stream: &ReadableStream,
+    underlying_source: SafeHandleValue,
+    high_water_mark: f64,
+) -> Fallible<()> {
+    let global = stream.global();
+    let controller = ReadableStreamDefaultController::new(&global);
 
-/// <https://streams.spec.whatwg.org/#set-up-readable-stream-default-controller>
-fn SetUpReadableStreamDefaultController() {}
+    rooted!(in(*cx) let underlying_source_obj = underlying_source.to_object_or_null());
+
+    rooted!(in(*cx) let mut start = UndefinedValue());
+    rooted!(in(*cx) let mut pull = UndefinedValue());
+    rooted!(in(*cx) let mut cancel = UndefinedValue());
+    rooted!(in(*cx) let mut size = UndefinedValue());
+
+    if !underlying_source_obj.get().is_null() {
+        unsafe {
+            get_dictionary_property(*cx, underlying_source_obj.handle(), "start", start.handle_mut())
+                .map_err(|_| Error::JSFailed)?;
+            get_dictionary_property(*cx, underlying_source_obj.handle(), "pull", pull.handle_mut())
+                .map_err(|_| Error::JSFailed)?;
+            get_dictionary_property(
+                *cx,
+                underlying_source_obj.handle(),
+                "cancel",
+                cancel.handle_mut(),
+            )
+            .map_err(|_| Error::JSFailed)?;
+            get_dictionary_property(*cx, underlying_source_obj.handle(), "size", size.handle_mut())
+                .map_err(|_| Error::JSFailed)?;
+        }
+    }
+
+    setup_readable_stream_default_controller(
+        cx,
+        stream,
+        &controller,
+        start.handle(),
+        pull.handle(),
+        cancel.handle(),
+        high_water_mark,
+        size.handle(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    //! These exercise the pure queue/state bookkeeping in
+    //! `ReadableStreamDefaultController` directly, including the
+    //! queue-emptiness branch of `close()` (via `request_close`) and the
+    //! pulling/pull-again reentrancy guard of `call_pull_if_needed` (via
+    //! `begin_pull`). The JS-engine-facing paths (`enqueue`'s
+    //! size-algorithm call, `desired_size`/`should_call_pull`'s use of
+    //! `self.stream()`, `setup_*`) need a live `ReadableStream`,
+    //! `JSContext`, and realm that this crate snapshot doesn't have the
+    //! pieces to construct, so they're covered by the WPT streams suite
+    //! instead.
+
+    use super::*;
+
+    fn new_test_controller() -> ReadableStreamDefaultController {
+        ReadableStreamDefaultController::new_inherited()
+    }
+
+    #[test]
+    fn request_close_with_empty_queue_clears_algorithms_and_signals_close() {
+        let controller = new_test_controller();
+        controller.pull_algorithm.set(ObjectValue(std::ptr::null_mut()));
+
+        let should_close_stream = controller.request_close();
+
+        assert!(should_close_stream);
+        assert!(controller.close_requested.get());
+        assert!(controller.pull_algorithm.get().is_null());
+    }
+
+    #[test]
+    fn request_close_with_pending_chunks_leaves_algorithms_until_drained() {
+        let controller = new_test_controller();
+        controller.pull_algorithm.set(ObjectValue(std::ptr::null_mut()));
+        controller.queue.borrow_mut().push_back(QueueEntry {
+            chunk: Heap::default(),
+            size: 1.0,
+        });
+
+        let should_close_stream = controller.request_close();
+
+        assert!(!should_close_stream);
+        assert!(controller.close_requested.get());
+        assert!(!controller.pull_algorithm.get().is_null());
+    }
+
+    #[test]
+    fn begin_pull_starts_a_pull_when_none_is_in_flight() {
+        let controller = new_test_controller();
+
+        assert!(controller.begin_pull());
+
+        assert!(controller.pulling.get());
+        assert!(!controller.pull_again.get());
+    }
+
+    #[test]
+    fn begin_pull_defers_instead_of_overlapping_an_in_flight_pull() {
+        let controller = new_test_controller();
+        controller.pulling.set(true);
+
+        assert!(!controller.begin_pull());
+
+        assert!(controller.pulling.get());
+        assert!(controller.pull_again.get());
+    }
+
+    #[test]
+    fn reset_queue_clears_size_and_entries() {
+        let controller = new_test_controller();
+        controller.queue.borrow_mut().push_back(QueueEntry {
+            chunk: Heap::default(),
+            size: 3.0,
+        });
+        controller.queue_total_size.set(3.0);
+
+        controller.reset_queue();
+
+        assert!(controller.queue.borrow().is_empty());
+        assert_eq!(controller.queue_total_size.get(), 0.0);
+    }
+
+    #[test]
+    fn clear_algorithms_nulls_every_callback() {
+        let controller = new_test_controller();
+        controller.pull_algorithm.set(ObjectValue(std::ptr::null_mut()));
+        controller.clear_algorithms();
+        assert!(controller.pull_algorithm.get().is_null());
+        assert!(controller.cancel_algorithm.get().is_null());
+        assert!(controller.strategy_size_algorithm.get().is_null());
+    }
+}